@@ -11,15 +11,31 @@
 //! `0x1` are not valid instances of type `0x0`, to avoid collision
 //! with NaN and Infinity).
 //!
-//! If even more polymorphism is needed, the low 3 bits
-//! (`POINTER_TAG_MASK`) can be used in pointer types as an additional
-//! tag, since modern allocators only allocate 8-byte aligned blocks.
+//! If even more polymorphism is needed, the free low bits of a
+//! pointer (`pointer_tags::PointerTag::TAG_MASK`, derived from the
+//! target's allocation alignment) can be used in pointer types as an
+//! additional tag.
+//!
+//! A note on FPU requantization. All four of bits 48-51 are used as
+//! tag bits, including bit 51 - the IEEE-754 "quiet" bit. A box whose
+//! tag leaves bit 51 clear (tags `0x0`-`0x7`) is therefore, strictly,
+//! a *signaling*-NaN bit pattern: handing it to an x87 or WASM
+//! floating-point register and storing it back can set the quiet bit
+//! and so rewrite the tag (`0x3` -> `0xb`). This crate does **not**
+//! defend against that, deliberately. A tagged word is only ever
+//! moved as an integer - it is never passed to the FPU as an `f64`,
+//! only `canonicalize_f64` touches the float path, and it runs on
+//! incoming floats before they are tagged - so the requantization
+//! path never arises. Reserving bit 51 to make the scheme robust to
+//! it would shrink the usable tag space to bits 48-50, i.e. eight
+//! tags of which `0x8` aliases the canonical NaN, leaving seven -
+//! fewer than the immediate types `nan_box` already defines. The
+//! trade-off is intentional and out of scope for this tagging scheme.
 
 pub const NAN_MASK: u64 = 0x7ff << 52;
 pub const TAG_SHIFT: usize = 48;
 pub const TAG_MASK: u64 = 0xf << 48;
 pub const RESERVED_BITS_MASK: u64 = NAN_MASK ^ TAG_MASK;
-// pub const POINTER_TAG_MASK: u64 = 0b111;
 pub const SIGN_MASK: u64 = 1 << 63;
 pub const RESERVED_BITS_AND_SIGN: u64 = RESERVED_BITS_MASK | SIGN_MASK;
 
@@ -52,7 +68,7 @@ pub const fn is_a_nan(n: u64) -> bool {
 /// * the NaN used by modern chips
 /// * negative the NaN used by modern chips
 pub fn is_the_nan_or_ifty(n: u64) -> bool {
-    f64::from_bits(n).is_infinite() || ((n & !SIGN_MASK) == core::f64::NAN.to_bits())
+    f64::from_bits(n).is_infinite() || ((n & !SIGN_MASK) == f64::NAN.to_bits())
 }
 
 pub fn is_nanbox(n: u64) -> bool {
@@ -84,15 +100,37 @@ pub const fn nan_tag(n: u64) -> u64 {
     n | NAN_MASK
 }
 
+/// Fold an incoming `f64` bit pattern so that user-produced NaNs can
+/// never be mistaken for a tagged NaN-box.
+///
+/// Any arithmetic-produced NaN carries an arbitrary mantissa payload
+/// (e.g. `NAN ^ 0x0005_5555`), which `is_nanbox` would happily read
+/// as a tagged immediate with some bogus tag. This collapses every
+/// such NaN to the single quiet-NaN pattern the crate reserves for a
+/// genuine NaN (`is_the_nan_or_ifty`), preserving only the sign bit.
+/// ±Infinity and the already-canonical NaN pass through untouched, as
+/// does every non-NaN value.
+///
+/// Only apply this to values arriving *as floats* (see
+/// `Word::from_f64`); a deliberately tagged word is itself a NaN with
+/// a meaningful payload and must not be fed back through here.
+pub fn canonicalize_f64(n: u64) -> u64 {
+    if is_a_nan(n) && !is_the_nan_or_ifty(n) {
+        (n & SIGN_MASK) | f64::NAN.to_bits()
+    } else {
+        n
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
     #[test]
     fn special_case_nans() {
-        assert!(!is_nanbox(std::f64::NAN.to_bits()));
-        assert!(!is_nanbox(std::f64::INFINITY.to_bits()));
-        assert!(!is_nanbox(std::f64::NEG_INFINITY.to_bits()));
-        assert!(!is_nanbox((-std::f64::NAN).to_bits()));
+        assert!(!is_nanbox(f64::NAN.to_bits()));
+        assert!(!is_nanbox(f64::INFINITY.to_bits()));
+        assert!(!is_nanbox(f64::NEG_INFINITY.to_bits()));
+        assert!(!is_nanbox((-f64::NAN).to_bits()));
     }
 
     #[test]
@@ -102,6 +140,29 @@ mod test {
         assert_eq!(tag_of(dead_beef), 0);
     }
 
+    #[test]
+    fn canonicalize_masked_nans() {
+        let nan = f64::NAN.to_bits();
+        // Flipping low mantissa bits leaves the exponent all-ones, so
+        // each of these is still a NaN but with a junk payload that
+        // `is_nanbox` would otherwise misread as a tagged value.
+        for mask in &[0x000A_AAAA_AAAAu64, 0x0005_5555_5555u64] {
+            let dirty = nan ^ mask;
+            assert!(is_a_nan(dirty));
+            assert!(is_nanbox(dirty), "0b{:064b} should look like a box", dirty);
+
+            let canon = canonicalize_f64(dirty);
+            assert_eq!(canon, nan);
+            assert!(!is_nanbox(canon));
+        }
+        // Non-NaN values and the reserved patterns are untouched.
+        assert_eq!(canonicalize_f64(3.5f64.to_bits()), 3.5f64.to_bits());
+        assert_eq!(
+            canonicalize_f64(f64::INFINITY.to_bits()),
+            f64::INFINITY.to_bits()
+        );
+    }
+
     #[test]
     fn signed_nanboxes() {
         let signed_int = -12345i64;
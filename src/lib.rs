@@ -1,7 +1,15 @@
 #![cfg_attr(feature = "no_std",  no_std)]
+// `failure`'s `#[derive(Fail)]` emits its `Display`/`Fail` impls inside an
+// anonymous const, which newer rustc flags as a non-local impl. The crate
+// leans on `failure` throughout, so silence it in one place.
+#![allow(non_local_definitions)]
 
 pub mod type_tags;
 pub mod bit_utils;
 pub mod pointer_tags;
+pub mod nan_box;
+pub mod word;
 
 pub use crate::type_tags::{TypeId, ThinTypeId, TypeError, TypeIdTooLargeError};
+pub use crate::nan_box::{NanBox, NanBoxable, UnpackError};
+pub use crate::word::Word;
@@ -0,0 +1,279 @@
+//! A typed front-end over the raw `bit_utils`/`type_tags` primitives.
+//!
+//! The low-level API makes the caller pick a `ThinTypeId`, remember
+//! which of the sixteen tags means what, and shift payloads in and
+//! out by hand. `NanBoxable` pushes that bookkeeping into the type
+//! system: each payload type names its own `TAG` and says how it
+//! turns into and out of the 48-bit-plus-sign payload, and `NanBox`
+//! packs and unpacks values without any hand-written shifting.
+
+use crate::bit_utils;
+use crate::type_tags::{ThinTypeId, TypeError};
+use failure::Fail;
+
+/// A type which can be stored inline in a `NanBox`.
+///
+/// Implementors promise that `into_payload` produces a value which is
+/// clean in the reserved bits (fits in 48 bits plus the sign), so it
+/// survives `ThinTypeId::tag`, and that `from_payload` is its inverse
+/// on the bits that `unsigned_untag` preserves.
+pub trait NanBoxable: Sized {
+    /// The tag under which values of this type are stored. Must not
+    /// be `0x0`, which is reserved for genuine NaN and Infinity.
+    const TAG: ThinTypeId;
+    /// Lower `self` into a taggable payload word.
+    fn into_payload(self) -> u64;
+    /// Recover a value from the payload word left behind by
+    /// `unsigned_untag`, or `None` if the bits are not a valid `Self`
+    /// (a `NanBox` rebuilt from untrusted bytes may carry a
+    /// well-tagged but out-of-range payload).
+    fn from_payload(payload: u64) -> Option<Self>;
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[repr(transparent)]
+/// A single NaN-boxed machine word: either a real `f64` or one of the
+/// sixteen tagged immediates.
+pub struct NanBox(u64);
+
+/// The three things a `NanBox` word can turn out to be.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Classification {
+    /// A real, usable floating-point value.
+    Float(f64),
+    /// One of the reserved NaN/Infinity bit patterns, which carry no
+    /// payload.
+    Infinity,
+    /// A tagged immediate stored under the given `ThinTypeId`.
+    Tagged(ThinTypeId),
+}
+
+/// The ways `NanBox::try_unpack` can fail.
+#[derive(Clone, Debug, Fail)]
+pub enum UnpackError {
+    /// The box holds some other tag than the requested type's.
+    #[fail(display = "{}", _0)]
+    WrongType(TypeError),
+    /// The tag matched, but the payload bits are not a valid value of
+    /// that type.
+    #[fail(display = "payload 0x{:012x} is not a valid value of tag {:?}", payload, tag)]
+    InvalidPayload { tag: ThinTypeId, payload: u64 },
+}
+
+impl From<TypeError> for UnpackError {
+    fn from(err: TypeError) -> Self {
+        UnpackError::WrongType(err)
+    }
+}
+
+impl NanBox {
+    /// Pack a `NanBoxable` value into a `NanBox`.
+    pub fn pack<T: NanBoxable>(value: T) -> NanBox {
+        NanBox(T::TAG.tag(value.into_payload()))
+    }
+
+    /// Unpack the value, erroring if this box does not hold a valid
+    /// `T`.
+    pub fn try_unpack<T: NanBoxable>(self) -> Result<T, UnpackError> {
+        let payload = T::TAG.try_unsigned_untag(self.0)?;
+        T::from_payload(payload).ok_or(UnpackError::InvalidPayload {
+            tag: T::TAG,
+            payload,
+        })
+    }
+
+    /// Wrap a real `f64`, canonicalizing it so a user-produced NaN
+    /// can never later `classify` as a tagged immediate (see
+    /// `bit_utils::canonicalize_f64`).
+    pub fn from_f64(n: f64) -> NanBox {
+        NanBox(bit_utils::canonicalize_f64(n.to_bits()))
+    }
+
+    /// Wrap a raw, already-boxed word.
+    pub const fn from_bits(bits: u64) -> NanBox {
+        NanBox(bits)
+    }
+
+    /// The raw word.
+    pub fn to_bits(self) -> u64 {
+        self.0
+    }
+
+    /// Determine whether this word is a real `f64`, a reserved
+    /// NaN/Infinity, or a tagged immediate of some tag.
+    pub fn classify(self) -> Classification {
+        if !bit_utils::is_a_nan(self.0) {
+            Classification::Float(f64::from_bits(self.0))
+        } else if bit_utils::is_the_nan_or_ifty(self.0) {
+            Classification::Infinity
+        } else {
+            Classification::Tagged(ThinTypeId::new(bit_utils::tag_of(self.0)))
+        }
+    }
+}
+
+impl NanBoxable for bool {
+    const TAG: ThinTypeId = ThinTypeId::new(0x1);
+    fn into_payload(self) -> u64 {
+        self as u64
+    }
+    fn from_payload(payload: u64) -> Option<Self> {
+        match payload {
+            0 => Some(false),
+            1 => Some(true),
+            _ => None,
+        }
+    }
+}
+
+impl NanBoxable for char {
+    const TAG: ThinTypeId = ThinTypeId::new(0x2);
+    fn into_payload(self) -> u64 {
+        u64::from(u32::from(self))
+    }
+    fn from_payload(payload: u64) -> Option<Self> {
+        u32::try_from(payload).ok().and_then(core::char::from_u32)
+    }
+}
+
+/// Implement `NanBoxable` for a primitive integer. The `as u64`
+/// conversion sign-extends signed values, so the reserved bits stay
+/// clean, and `unsigned_untag` keeps the low 48 bits. `from_payload`
+/// rejects any payload whose low 48 bits are not the `into_payload`
+/// encoding of some `$ty` - i.e. junk in the bits above the type's
+/// width (or not matching the sign extension, for signed types) -
+/// so a box rebuilt from untrusted bytes cannot masquerade as a
+/// valid integer.
+macro_rules! nan_boxable_int {
+    ($($ty:ty => $tag:expr),* $(,)?) => {$(
+        impl NanBoxable for $ty {
+            const TAG: ThinTypeId = ThinTypeId::new($tag);
+            fn into_payload(self) -> u64 {
+                self as u64
+            }
+            fn from_payload(payload: u64) -> Option<Self> {
+                const PAYLOAD_MASK: u64 =
+                    !(bit_utils::RESERVED_BITS_MASK | bit_utils::SIGN_MASK);
+                let value = payload as $ty;
+                if payload == (value as u64) & PAYLOAD_MASK {
+                    Some(value)
+                } else {
+                    None
+                }
+            }
+        }
+    )*};
+}
+
+// Immediates are packed onto the low tags; tags `0x1`-`0x7` leave the
+// quiet bit (51) clear, which is fine because a tagged word is never
+// fed through the FPU - see the note on FPU requantization in the
+// `bit_utils` module docs for why this scheme does not reserve bit 51.
+//
+// Tag `0x8` is skipped: with a zero payload its word is
+// `0x7ff8_0000_0000_0000`, bit-identical to the canonical quiet NaN
+// (`f64::NAN`), so a value stored under it - e.g. `0i32` - would read
+// back as `Infinity` instead of round-tripping. Tags `0x9`..=`0xf` set
+// the same high mantissa bit but differ from the canonical NaN for
+// every payload, so they are safe.
+nan_boxable_int! {
+    u8 => 0x3,
+    u16 => 0x4,
+    u32 => 0x5,
+    i8 => 0x6,
+    i16 => 0x7,
+    i32 => 0x9,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn round_trip_bool() {
+        for b in [true, false] {
+            let boxed = NanBox::pack(b);
+            assert_eq!(boxed.try_unpack::<bool>().unwrap(), b);
+        }
+    }
+
+    #[test]
+    fn round_trip_char() {
+        for c in ['a', 'Z', '0', 'λ', '🥄'] {
+            let boxed = NanBox::pack(c);
+            assert_eq!(boxed.try_unpack::<char>().unwrap(), c);
+        }
+    }
+
+    #[test]
+    fn round_trip_ints() {
+        assert_eq!(NanBox::pack(200u8).try_unpack::<u8>().unwrap(), 200);
+        assert_eq!(NanBox::pack(40_000u16).try_unpack::<u16>().unwrap(), 40_000);
+        assert_eq!(NanBox::pack(-12345i32).try_unpack::<i32>().unwrap(), -12345);
+        assert_eq!(NanBox::pack(-7i8).try_unpack::<i8>().unwrap(), -7);
+    }
+
+    #[test]
+    fn zero_payloads_round_trip_as_tagged() {
+        // A zero payload must not alias the canonical NaN (tag 0x8):
+        // each of these has to classify as `Tagged`, not `Infinity`,
+        // and round-trip back to zero.
+        assert_eq!(NanBox::pack(0i32).try_unpack::<i32>().unwrap(), 0);
+        assert_eq!(NanBox::pack(0u8).try_unpack::<u8>().unwrap(), 0);
+        assert!(!NanBox::pack(false).try_unpack::<bool>().unwrap());
+        match NanBox::pack(0i32).classify() {
+            Classification::Tagged(tag) => assert_eq!(tag, i32::TAG),
+            other => panic!("expected Tagged, found {:?}", other),
+        }
+    }
+
+    #[test]
+    fn wrong_tag_errors() {
+        let boxed = NanBox::pack(true);
+        assert!(boxed.try_unpack::<char>().is_err());
+    }
+
+    #[test]
+    fn out_of_range_int_and_bool_payloads_error() {
+        // Junk in the bits above the type's width must not be masked
+        // down to a spurious in-range value.
+        let too_wide = NanBox::from_bits(u8::TAG.tag(0x1FF));
+        assert!(matches!(
+            too_wide.try_unpack::<u8>(),
+            Err(UnpackError::InvalidPayload { .. })
+        ));
+        // A bool payload other than 0 or 1 is not a valid `bool`.
+        let bad_bool = NanBox::from_bits(bool::TAG.tag(2));
+        assert!(matches!(
+            bad_bool.try_unpack::<bool>(),
+            Err(UnpackError::InvalidPayload { .. })
+        ));
+        // Negative signed values still round-trip (sign extension is
+        // accepted, not rejected).
+        assert_eq!(NanBox::pack(-1i32).try_unpack::<i32>().unwrap(), -1);
+    }
+
+    #[test]
+    fn bad_char_payload_errors() {
+        // A surrogate is a well-tagged but out-of-range `char`
+        // payload; unpacking it must error, not panic.
+        let boxed = NanBox::from_bits(char::TAG.tag(0xD800));
+        assert!(matches!(
+            boxed.try_unpack::<char>(),
+            Err(UnpackError::InvalidPayload { .. })
+        ));
+    }
+
+    #[test]
+    fn classify_float_and_tagged() {
+        assert_eq!(NanBox::from_f64(3.5).classify(), Classification::Float(3.5));
+        match NanBox::pack(42u32).classify() {
+            Classification::Tagged(tag) => assert_eq!(tag, u32::TAG),
+            other => panic!("expected Tagged, found {:?}", other),
+        }
+        assert_eq!(
+            NanBox::from_f64(f64::INFINITY).classify(),
+            Classification::Infinity
+        );
+    }
+}
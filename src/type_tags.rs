@@ -3,10 +3,24 @@ use core::convert::TryFrom;
 use failure::Fail;
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "zerocopy",
+    derive(
+        zerocopy::FromBytes,
+        zerocopy::IntoBytes,
+        zerocopy::Immutable,
+        zerocopy::KnownLayout
+    )
+)]
 #[repr(transparent)]
 /// A type tag. When stored in this form, it must have its high 16
 /// bits clean - that is, `bit_utils::has_reserved_bits(self.0)` must
 /// return `false`.
+///
+/// The optional `zerocopy` derives parse *any* eight bytes into a
+/// `TypeId` without checking that invariant, so bytes read off the
+/// wire must be validated (see `Word::from_bytes_validated`) before
+/// the tag is trusted.
 pub struct TypeId(u64);
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
@@ -54,6 +68,12 @@ impl From<ThinTypeId> for TypeId {
 }
 
 impl ThinTypeId {
+    /// Construct a `ThinTypeId` from a raw four-bit tag. Panics
+    /// (debug only) if `tag` does not fit in the low four bits.
+    pub const fn new(tag: u8) -> Self {
+        debug_assert!((tag & 0xf0) == 0);
+        ThinTypeId(tag)
+    }
     /// Panics (debug only) if `self` is malformed. A no-op in release
     /// builds.
     fn assert_size(self) {
@@ -0,0 +1,149 @@
+//! The raw machine word of a NaN-boxed heap.
+//!
+//! A tagged heap is just a block of `u64`s. `Word` is the
+//! `#[repr(transparent)]` newtype over one such word; it exists so
+//! that the canonicalization described in `bit_utils` happens on
+//! every `f64` that enters the heap, and so later layers (e.g.
+//! zerocopy serialization) have a single concrete type to hang impls
+//! on.
+
+use crate::bit_utils;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "zerocopy",
+    derive(
+        zerocopy::FromBytes,
+        zerocopy::IntoBytes,
+        zerocopy::Immutable,
+        zerocopy::KnownLayout
+    )
+)]
+#[repr(transparent)]
+pub struct Word(pub(crate) u64);
+
+impl Word {
+    /// Store an `f64`, canonicalizing it so a user-produced NaN can
+    /// never be read back as a tagged immediate.
+    pub fn from_f64(n: f64) -> Word {
+        Word(bit_utils::canonicalize_f64(n.to_bits()))
+    }
+
+    /// Reinterpret the word as an `f64`.
+    pub fn to_f64(self) -> f64 {
+        f64::from_bits(self.0)
+    }
+
+    /// Wrap an already-tagged word without canonicalizing it.
+    pub const fn from_bits(bits: u64) -> Word {
+        Word(bits)
+    }
+
+    /// The raw bits.
+    pub const fn to_bits(self) -> u64 {
+        self.0
+    }
+}
+
+/// Feature-gated zerocopy integration: read and write a `Word` (and,
+/// by extension, whole tagged heap images) as bytes with defined
+/// endianness, so an image can travel between processes or machines.
+#[cfg(feature = "zerocopy")]
+mod serialize {
+    use super::Word;
+    use crate::bit_utils;
+    use failure::Fail;
+    use zerocopy::byteorder::{BigEndian, LittleEndian, U64};
+    use zerocopy::FromBytes;
+
+    /// A `Word` laid out little-endian on the wire, analogous to
+    /// zerocopy's `U64<LittleEndian>`.
+    pub type WordLe = U64<LittleEndian>;
+    /// A `Word` laid out big-endian on the wire.
+    pub type WordBe = U64<BigEndian>;
+
+    #[derive(Copy, Clone, Debug, Fail)]
+    pub enum FromBytesError {
+        #[fail(display = "expected 8 bytes, found {}", _0)]
+        WrongLength(usize),
+        #[fail(display = "word claims the reserved tag 0x0 with a non-NaN payload")]
+        ReservedTag,
+    }
+
+    impl Word {
+        /// Serialize as little-endian bytes.
+        pub fn to_le(self) -> WordLe {
+            U64::new(self.0)
+        }
+
+        /// Serialize as big-endian bytes.
+        pub fn to_be(self) -> WordBe {
+            U64::new(self.0)
+        }
+
+        /// Parse a `Word` from a little-endian byte buffer, running
+        /// the same canonicalization and reserved-tag checks a
+        /// freshly-tagged value would, so the payload can be trusted.
+        pub fn from_bytes_validated(bytes: &[u8]) -> Result<Word, FromBytesError> {
+            let raw = U64::<LittleEndian>::read_from_bytes(bytes)
+                .map_err(|_| FromBytesError::WrongLength(bytes.len()))?
+                .get();
+
+            if bit_utils::is_nanbox(raw) {
+                // Tag 0x0 is reserved for genuine NaN/Infinity; a box
+                // that claims it is malformed.
+                if bit_utils::tag_of(raw) == 0 {
+                    return Err(FromBytesError::ReservedTag);
+                }
+                Ok(Word(raw))
+            } else {
+                // A real float: fold any stray NaN payload so it can
+                // never later be misread as a box.
+                Ok(Word(bit_utils::canonicalize_f64(raw)))
+            }
+        }
+    }
+}
+
+#[cfg(feature = "zerocopy")]
+pub use serialize::{FromBytesError, WordBe, WordLe};
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bit_utils;
+
+    #[test]
+    fn canonicalizes_on_entry() {
+        let dirty = f64::NAN.to_bits() ^ 0x0005_5555_5555;
+        let word = Word::from_f64(f64::from_bits(dirty));
+        assert!(!bit_utils::is_nanbox(word.to_bits()));
+        assert!(word.to_f64().is_nan());
+    }
+
+    #[test]
+    fn plain_floats_survive() {
+        let word = Word::from_f64(1.5);
+        assert_eq!(word.to_f64(), 1.5);
+    }
+
+    #[cfg(feature = "zerocopy")]
+    #[test]
+    fn round_trip_bytes_little_endian() {
+        use crate::type_tags::ThinTypeId;
+        let word = Word::from_bits(ThinTypeId::new(0x5).tag(0xdead_beef));
+        let bytes = word.to_le();
+        let parsed = Word::from_bytes_validated(zerocopy::IntoBytes::as_bytes(&bytes)).unwrap();
+        assert_eq!(parsed, word);
+    }
+
+    #[cfg(feature = "zerocopy")]
+    #[test]
+    fn rejects_reserved_tag_and_folds_nan() {
+        // Tag 0x0 with a junk payload is a malformed box.
+        let bogus = bit_utils::nan_tag(0x2);
+        assert!(bit_utils::is_nanbox(bogus) && bit_utils::tag_of(bogus) == 0);
+        let bytes = zerocopy::byteorder::U64::<zerocopy::byteorder::LittleEndian>::new(bogus);
+        assert!(Word::from_bytes_validated(zerocopy::IntoBytes::as_bytes(&bytes)).is_err());
+    }
+}